@@ -1,18 +1,25 @@
+use mpl_token_metadata::instruction::builders::TransferBuilder;
+use mpl_token_metadata::instruction::{InstructionBuilder, TransferArgs};
+use mpl_token_metadata::state::{Metadata, TokenMetadataAccount};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
     entrypoint,
     entrypoint::ProgramResult,
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
     pubkey::Pubkey,
-    system_instruction,
-    sysvar::{rent::Rent, Sysvar},
+    system_instruction, system_program,
+    sysvar::{rent, rent::Rent, Sysvar},
 };
 use spl_token::instruction as token_instruction;
 
 entrypoint!(process_instruction);
 
+/// Protocol fee taken out of every sale, in basis points, paid into the treasury PDA.
+pub const FEE_BPS: u64 = 250;
+
 #[derive(Debug)]
 pub enum MarketplaceInstruction {
     /// Lists an NFT for sale
@@ -24,18 +31,185 @@ pub enum MarketplaceInstruction {
     /// 5. `[]` System program
     /// 6. `[]` Token program
     /// 7. `[]` Rent sysvar
-    ListNFT { price: u64 },
+    ///
+    /// When `is_pnft` is set, the mint is a programmable NFT and these accounts
+    /// must also be present, in this order:
+    /// 8. `[]` Token metadata account
+    /// 9. `[]` Master edition account
+    /// 10. `[writable]` Seller's token record
+    /// 11. `[writable]` Escrow token record
+    /// 12. `[]` Token metadata program
+    /// 13. `[]` Instructions sysvar
+    /// 14. `[]` SPL associated token program
+    /// 15. `[]` Token auth rules program
+    /// 16. `[]` Token auth rules account (rule set)
+    ListNFT { price: u64, is_pnft: bool },
 
-    /// Purchases a listed NFT
+    /// Purchases a listed NFT, paying creator royalties and the protocol fee
+    /// out of the sale price
     /// 0. `[signer]` The buyer
     /// 1. `[writable]` The seller
     /// 2. `[writable]` Escrow token account
     /// 3. `[writable]` Buyer's token account
     /// 4. `[writable]` Escrow state account
     /// 5. `[]` NFT mint
-    /// 6. `[]` System program
-    /// 7. `[]` Token program
-    PurchaseNFT { price: u64 },
+    /// 6. `[]` Token metadata account (Metaplex PDA for the mint)
+    /// 7. `[writable]` Treasury PDA
+    /// 8. `[]` System program
+    /// 9. `[]` Token program
+    ///
+    /// When `is_pnft` is set, the mint is a programmable NFT and these accounts
+    /// must also be present, in this order, before the trailing creator accounts:
+    /// 10. `[]` Master edition account
+    /// 11. `[writable]` Escrow token record
+    /// 12. `[writable]` Buyer's token record
+    /// 13. `[]` Token metadata program
+    /// 14. `[]` Instructions sysvar
+    /// 15. `[]` SPL associated token program
+    /// 16. `[]` Token auth rules program
+    /// 17. `[]` Token auth rules account (rule set)
+    ///
+    /// 18.. `[writable]` Creator accounts, in the order listed in the NFT's metadata
+    PurchaseNFT { price: u64, is_pnft: bool },
+
+    /// Cancels a listing, returning the escrowed NFT to the seller
+    /// 0. `[signer]` The seller
+    /// 1. `[writable]` Escrow token account
+    /// 2. `[writable]` Seller's token account
+    /// 3. `[writable]` Escrow state account
+    /// 4. `[]` NFT mint
+    /// 5. `[]` Token program
+    CancelListing,
+
+    /// Sweeps accumulated protocol fees out of the treasury PDA, leaving the
+    /// rent-exempt minimum behind. Restricted to this program's upgrade authority.
+    /// 0. `[signer]` The program's upgrade authority
+    /// 1. `[writable]` Treasury PDA
+    /// 2. `[writable]` Destination account
+    /// 3. `[]` This program's ProgramData account (owned by the upgradeable loader)
+    /// 4. `[]` System program
+    /// 5. `[]` Rent sysvar
+    SweepFees,
+
+    /// Places a bid on an NFT, escrowing the bid amount
+    /// 0. `[signer]` The bidder
+    /// 1. `[writable]` Bid escrow-payment PDA (`["bid", mint, bidder]`)
+    /// 2. `[writable]` Bid state account (`["bid_state", mint, bidder]`)
+    /// 3. `[]` NFT mint
+    /// 4. `[]` System program
+    /// 5. `[]` Rent sysvar
+    PlaceBid { amount: u64 },
+
+    /// Accepts an outstanding bid, selling the escrowed NFT to the bidder
+    /// 0. `[signer]` The seller
+    /// 1. `[writable]` Escrow token account
+    /// 2. `[writable]` Bidder's token account
+    /// 3. `[writable]` Escrow state account
+    /// 4. `[]` NFT mint
+    /// 5. `[]` Token metadata account (Metaplex PDA for the mint)
+    /// 6. `[writable]` Treasury PDA
+    /// 7. `[writable]` Bid escrow-payment PDA
+    /// 8. `[writable]` Bid state account
+    /// 9. `[writable]` The bidder, to receive the bid state account's rent back
+    /// 10. `[]` System program
+    /// 11. `[]` Token program
+    /// 12.. `[writable]` Creator accounts, in the order listed in the NFT's metadata
+    AcceptBid,
+
+    /// Cancels an outstanding bid, refunding the escrowed lamports to the bidder
+    /// 0. `[signer]` The bidder
+    /// 1. `[writable]` Bid escrow-payment PDA
+    /// 2. `[writable]` Bid state account
+    /// 3. `[]` NFT mint
+    /// 4. `[]` System program
+    CancelBid,
+}
+
+/// On-chain state for an escrowed listing.
+///
+/// Serialized with a fixed layout so it can be read back byte-for-byte:
+/// a one-byte initialized tag, followed by the seller and mint pubkeys,
+/// followed by the little-endian price.
+#[derive(Debug, Default, PartialEq)]
+pub struct EscrowState {
+    pub is_initialized: bool,
+    pub seller: Pubkey,
+    pub nft_mint: Pubkey,
+    pub price: u64,
+}
+
+impl EscrowState {
+    pub const LEN: usize = 73;
+
+    pub fn serialize(&self, dst: &mut [u8]) {
+        dst[0] = self.is_initialized as u8;
+        dst[1..33].copy_from_slice(self.seller.as_ref());
+        dst[33..65].copy_from_slice(self.nft_mint.as_ref());
+        dst[65..73].copy_from_slice(&self.price.to_le_bytes());
+    }
+
+    pub fn deserialize(src: &[u8]) -> Result<Self, ProgramError> {
+        let src: &[u8; Self::LEN] = src
+            .get(..Self::LEN)
+            .and_then(|slice| slice.try_into().ok())
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        let is_initialized = match src[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let seller = Pubkey::new_from_array(src[1..33].try_into().unwrap());
+        let nft_mint = Pubkey::new_from_array(src[33..65].try_into().unwrap());
+        let price = u64::from_le_bytes(src[65..73].try_into().unwrap());
+
+        Ok(Self {
+            is_initialized,
+            seller,
+            nft_mint,
+            price,
+        })
+    }
+}
+
+/// On-chain state for an outstanding bid, paired with the `["bid", mint, bidder]`
+/// PDA that escrows the bid's lamports.
+#[derive(Debug, Default, PartialEq)]
+pub struct BidState {
+    pub is_initialized: bool,
+    pub bidder: Pubkey,
+    pub amount: u64,
+}
+
+impl BidState {
+    pub const LEN: usize = 41;
+
+    pub fn serialize(&self, dst: &mut [u8]) {
+        dst[0] = self.is_initialized as u8;
+        dst[1..33].copy_from_slice(self.bidder.as_ref());
+        dst[33..41].copy_from_slice(&self.amount.to_le_bytes());
+    }
+
+    pub fn deserialize(src: &[u8]) -> Result<Self, ProgramError> {
+        let src: &[u8; Self::LEN] = src
+            .get(..Self::LEN)
+            .and_then(|slice| slice.try_into().ok())
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        let is_initialized = match src[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let bidder = Pubkey::new_from_array(src[1..33].try_into().unwrap());
+        let amount = u64::from_le_bytes(src[33..41].try_into().unwrap());
+
+        Ok(Self {
+            is_initialized,
+            bidder,
+            amount,
+        })
+    }
 }
 
 pub fn process_instruction(
@@ -46,19 +220,200 @@ pub fn process_instruction(
     let instruction = MarketplaceInstruction::unpack(instruction_data)?;
 
     match instruction {
-        MarketplaceInstruction::ListNFT { price } => {
-            process_list(program_id, accounts, price)
+        MarketplaceInstruction::ListNFT { price, is_pnft } => {
+            process_list(program_id, accounts, price, is_pnft)
+        }
+        MarketplaceInstruction::PurchaseNFT { price, is_pnft } => {
+            process_purchase(program_id, accounts, price, is_pnft)
+        }
+        MarketplaceInstruction::CancelListing => {
+            process_cancel(program_id, accounts)
+        }
+        MarketplaceInstruction::SweepFees => {
+            process_sweep_fees(program_id, accounts)
+        }
+        MarketplaceInstruction::PlaceBid { amount } => {
+            process_place_bid(program_id, accounts, amount)
+        }
+        MarketplaceInstruction::AcceptBid => {
+            process_accept_bid(program_id, accounts)
+        }
+        MarketplaceInstruction::CancelBid => {
+            process_cancel_bid(program_id, accounts)
+        }
+    }
+}
+
+/// Moves one unit of a programmable NFT between token accounts via the
+/// token-metadata program's transfer CPI, since the plain SPL `transfer`
+/// instruction is rejected for `ProgrammableNonFungible` mints.
+#[allow(clippy::too_many_arguments)]
+fn transfer_pnft<'a>(
+    token: &AccountInfo<'a>,
+    token_owner: &AccountInfo<'a>,
+    destination_token: &AccountInfo<'a>,
+    destination_owner: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    metadata: &AccountInfo<'a>,
+    edition: &AccountInfo<'a>,
+    owner_token_record: &AccountInfo<'a>,
+    destination_token_record: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    sysvar_instructions: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    spl_ata_program: &AccountInfo<'a>,
+    token_metadata_program: &AccountInfo<'a>,
+    auth_rules_program: &AccountInfo<'a>,
+    auth_rules: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let transfer_ix = TransferBuilder::new()
+        .token(*token.key)
+        .token_owner(*token_owner.key)
+        .destination(*destination_token.key)
+        .destination_owner(*destination_owner.key)
+        .mint(*mint.key)
+        .metadata(*metadata.key)
+        .edition(*edition.key)
+        .owner_token_record(*owner_token_record.key)
+        .destination_token_record(*destination_token_record.key)
+        .authority(*authority.key)
+        .payer(*payer.key)
+        .system_program(*system_program.key)
+        .sysvar_instructions(*sysvar_instructions.key)
+        .spl_token_program(*token_program.key)
+        .spl_ata_program(*spl_ata_program.key)
+        .authorization_rules_program(*auth_rules_program.key)
+        .authorization_rules(*auth_rules.key)
+        .build(TransferArgs::V1 {
+            amount: 1,
+            authorization_data: None,
+        })
+        .map_err(|_| ProgramError::InvalidArgument)?
+        .instruction();
+
+    invoke_signed(
+        &transfer_ix,
+        &[
+            token.clone(),
+            token_owner.clone(),
+            destination_token.clone(),
+            destination_owner.clone(),
+            mint.clone(),
+            metadata.clone(),
+            edition.clone(),
+            owner_token_record.clone(),
+            destination_token_record.clone(),
+            authority.clone(),
+            payer.clone(),
+            system_program.clone(),
+            sysvar_instructions.clone(),
+            token_program.clone(),
+            spl_ata_program.clone(),
+            auth_rules_program.clone(),
+            auth_rules.clone(),
+            token_metadata_program.clone(),
+        ],
+        signer_seeds,
+    )
+}
+
+/// Splits `price` between each creator's royalty share and the protocol fee,
+/// in that fixed-point order, returning `(creator_shares, royalty_paid, fee)`.
+/// `creator_shares` is aligned with `creator_share_percents`. Pulled out of
+/// `pay_royalties_and_fee` so the arithmetic can be unit-tested without a
+/// Solana runtime to back the CPI transfers.
+fn split_sale_proceeds(
+    price: u64,
+    seller_fee_basis_points: u16,
+    creator_share_percents: &[u8],
+) -> Result<(Vec<u64>, u64, u64), ProgramError> {
+    let royalty = (price as u128)
+        .checked_mul(seller_fee_basis_points as u128)
+        .ok_or(ProgramError::InvalidArgument)?
+        / 10_000;
+
+    let mut creator_shares = Vec::with_capacity(creator_share_percents.len());
+    let mut royalty_paid: u64 = 0;
+    for share in creator_share_percents {
+        let creator_share = (royalty * *share as u128 / 100) as u64;
+        creator_shares.push(creator_share);
+        royalty_paid = royalty_paid
+            .checked_add(creator_share)
+            .ok_or(ProgramError::InvalidArgument)?;
+    }
+
+    let fee = (price as u128)
+        .checked_mul(FEE_BPS as u128)
+        .ok_or(ProgramError::InvalidArgument)?
+        / 10_000;
+    let fee = fee as u64;
+
+    Ok((creator_shares, royalty_paid, fee))
+}
+
+/// Splits `price` between the NFT's creator royalties and the protocol fee,
+/// paying both out of `payer`, and returns `(royalty_paid, fee)` so the
+/// caller can work out what's left over for the seller.
+#[allow(clippy::too_many_arguments)]
+fn pay_royalties_and_fee<'a>(
+    price: u64,
+    metadata: &Metadata,
+    creator_accounts: &[&AccountInfo<'a>],
+    treasury: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    payer_signer_seeds: &[&[&[u8]]],
+    system_program: &AccountInfo<'a>,
+) -> Result<(u64, u64), ProgramError> {
+    let creators = metadata.data.creators.clone().unwrap_or_default();
+    if creators.len() != creator_accounts.len() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    for (creator, creator_account) in creators.iter().zip(creator_accounts.iter()) {
+        if &creator.address != creator_account.key {
+            return Err(ProgramError::InvalidArgument);
         }
-        MarketplaceInstruction::PurchaseNFT { price } => {
-            process_purchase(program_id, accounts, price)
+    }
+
+    let creator_share_percents: Vec<u8> = creators.iter().map(|creator| creator.share).collect();
+    let (creator_shares, royalty_paid, fee) = split_sale_proceeds(
+        price,
+        metadata.data.seller_fee_basis_points,
+        &creator_share_percents,
+    )?;
+
+    for (creator_account, creator_share) in creator_accounts.iter().zip(creator_shares.iter()) {
+        if *creator_share > 0 {
+            invoke_signed(
+                &system_instruction::transfer(payer.key, creator_account.key, *creator_share),
+                &[
+                    payer.clone(),
+                    (*creator_account).clone(),
+                    system_program.clone(),
+                ],
+                payer_signer_seeds,
+            )?;
         }
     }
+
+    if fee > 0 {
+        invoke_signed(
+            &system_instruction::transfer(payer.key, treasury.key, fee),
+            &[payer.clone(), treasury.clone(), system_program.clone()],
+            payer_signer_seeds,
+        )?;
+    }
+
+    Ok((royalty_paid, fee))
 }
 
 fn process_list(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     price: u64,
+    is_pnft: bool,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let seller = next_account_info(account_info_iter)?;
@@ -69,6 +424,21 @@ fn process_list(
     let system_program = next_account_info(account_info_iter)?;
     let token_program = next_account_info(account_info_iter)?;
     let rent = next_account_info(account_info_iter)?;
+    let pnft_accounts = if is_pnft {
+        Some((
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+        ))
+    } else {
+        None
+    };
 
     // Verify the seller signed the transaction
     if !seller.is_signer {
@@ -90,24 +460,95 @@ fn process_list(
         return Err(ProgramError::InvalidArgument);
     }
 
-    // Transfer NFT to escrow
-    invoke(
-        &token_instruction::transfer(
-            token_program.key,
-            seller_token.key,
-            escrow_token.key,
+    // Create the escrow state account, owned by this program, sized to hold EscrowState
+    if rent.key != &rent::ID {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let rent = Rent::from_account_info(rent)?;
+    let lamports = rent.minimum_balance(EscrowState::LEN);
+
+    invoke_signed(
+        &system_instruction::create_account(
             seller.key,
-            &[],
-            1,
-        )?,
+            escrow_state.key,
+            lamports,
+            EscrowState::LEN as u64,
+            program_id,
+        ),
         &[
-            seller_token.clone(),
-            escrow_token.clone(),
             seller.clone(),
-            token_program.clone(),
+            escrow_state.clone(),
+            system_program.clone(),
         ],
+        &[&[
+            b"escrow",
+            nft_mint.key.as_ref(),
+            seller.key.as_ref(),
+            &[bump_seed],
+        ]],
     )?;
 
+    let state = EscrowState {
+        is_initialized: true,
+        seller: *seller.key,
+        nft_mint: *nft_mint.key,
+        price,
+    };
+    state.serialize(&mut escrow_state.try_borrow_mut_data()?);
+
+    // Transfer NFT to escrow
+    if let Some((
+        metadata,
+        edition,
+        seller_token_record,
+        escrow_token_record,
+        token_metadata_program,
+        sysvar_instructions,
+        spl_ata_program,
+        auth_rules_program,
+        auth_rules,
+    )) = pnft_accounts
+    {
+        transfer_pnft(
+            seller_token,
+            seller,
+            escrow_token,
+            escrow_state,
+            nft_mint,
+            metadata,
+            edition,
+            seller_token_record,
+            escrow_token_record,
+            seller,
+            seller,
+            system_program,
+            sysvar_instructions,
+            token_program,
+            spl_ata_program,
+            token_metadata_program,
+            auth_rules_program,
+            auth_rules,
+            &[],
+        )?;
+    } else {
+        invoke(
+            &token_instruction::transfer(
+                token_program.key,
+                seller_token.key,
+                escrow_token.key,
+                seller.key,
+                &[],
+                1,
+            )?,
+            &[
+                seller_token.clone(),
+                escrow_token.clone(),
+                seller.clone(),
+                token_program.clone(),
+            ],
+        )?;
+    }
+
     msg!("NFT listed for {} lamports", price);
     Ok(())
 }
@@ -115,7 +556,8 @@ fn process_list(
 fn process_purchase(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    price: u64,
+    _price: u64,
+    is_pnft: bool,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let buyer = next_account_info(account_info_iter)?;
@@ -124,17 +566,82 @@ fn process_purchase(
     let buyer_token = next_account_info(account_info_iter)?;
     let escrow_state = next_account_info(account_info_iter)?;
     let nft_mint = next_account_info(account_info_iter)?;
+    let metadata_info = next_account_info(account_info_iter)?;
+    let treasury = next_account_info(account_info_iter)?;
     let system_program = next_account_info(account_info_iter)?;
     let token_program = next_account_info(account_info_iter)?;
+    let pnft_accounts = if is_pnft {
+        Some((
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+            next_account_info(account_info_iter)?,
+        ))
+    } else {
+        None
+    };
+    let creator_accounts: Vec<&AccountInfo> = account_info_iter.collect();
 
     // Verify the buyer signed the transaction
     if !buyer.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    // Transfer SOL from buyer to seller
+    // The listing's price lives on-chain in escrow_state; the instruction's own
+    // `price` argument is untrusted and must never drive the transfer.
+    let state = EscrowState::deserialize(&escrow_state.try_borrow_data()?)?;
+    if !state.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if &state.seller != seller.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &state.nft_mint != nft_mint.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let price = state.price;
+
+    // Verify the metadata account and split off creator royalties before paying the seller
+    let (metadata_pda, _) = Pubkey::find_program_address(
+        &[
+            b"metadata",
+            mpl_token_metadata::ID.as_ref(),
+            nft_mint.key.as_ref(),
+        ],
+        &mpl_token_metadata::ID,
+    );
+    if metadata_info.key != &metadata_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let metadata = Metadata::from_account_info(metadata_info)?;
+
+    let (treasury_pda, _) = Pubkey::find_program_address(&[b"treasury", program_id.as_ref()], program_id);
+    if treasury.key != &treasury_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (royalty_paid, fee) = pay_royalties_and_fee(
+        price,
+        &metadata,
+        &creator_accounts,
+        treasury,
+        buyer,
+        &[],
+        system_program,
+    )?;
+
+    let seller_proceeds = price
+        .checked_sub(royalty_paid)
+        .and_then(|remainder| remainder.checked_sub(fee))
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    // Transfer the remaining SOL from buyer to seller
     invoke(
-        &system_instruction::transfer(buyer.key, seller.key, price),
+        &system_instruction::transfer(buyer.key, seller.key, seller_proceeds),
         &[
             buyer.clone(),
             seller.clone(),
@@ -151,19 +658,423 @@ fn process_purchase(
         ],
         program_id,
     );
+    if escrow_state.key != &escrow_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let escrow_signer_seeds: &[&[u8]] = &[
+        b"escrow",
+        nft_mint.key.as_ref(),
+        seller.key.as_ref(),
+        &[bump_seed],
+    ];
+
+    if let Some((
+        edition,
+        escrow_token_record,
+        buyer_token_record,
+        token_metadata_program,
+        sysvar_instructions,
+        spl_ata_program,
+        auth_rules_program,
+        auth_rules,
+    )) = pnft_accounts
+    {
+        transfer_pnft(
+            escrow_token,
+            escrow_state,
+            buyer_token,
+            buyer,
+            nft_mint,
+            metadata_info,
+            edition,
+            escrow_token_record,
+            buyer_token_record,
+            escrow_state,
+            buyer,
+            system_program,
+            sysvar_instructions,
+            token_program,
+            spl_ata_program,
+            token_metadata_program,
+            auth_rules_program,
+            auth_rules,
+            &[escrow_signer_seeds],
+        )?;
+    } else {
+        invoke_signed(
+            &token_instruction::transfer(
+                token_program.key,
+                escrow_token.key,
+                buyer_token.key,
+                &escrow_pda,
+                &[],
+                1,
+            )?,
+            &[
+                escrow_token.clone(),
+                buyer_token.clone(),
+                escrow_state.clone(),
+                token_program.clone(),
+            ],
+            &[escrow_signer_seeds],
+        )?;
+    }
+
+    // Close the escrow state account, returning its rent to the seller
+    let escrow_lamports = escrow_state.lamports();
+    **escrow_state.try_borrow_mut_lamports()? = 0;
+    **seller.try_borrow_mut_lamports()? += escrow_lamports;
+    escrow_state.realloc(0, false)?;
+    escrow_state.assign(&system_program::ID);
+
+    msg!(
+        "NFT purchased for {} lamports ({} in royalties, {} in protocol fees)",
+        price,
+        royalty_paid,
+        fee
+    );
+    Ok(())
+}
+
+fn process_cancel(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let seller = next_account_info(account_info_iter)?;
+    let escrow_token = next_account_info(account_info_iter)?;
+    let seller_token = next_account_info(account_info_iter)?;
+    let escrow_state = next_account_info(account_info_iter)?;
+    let nft_mint = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+
+    // Verify the seller signed the transaction
+    if !seller.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let state = EscrowState::deserialize(&escrow_state.try_borrow_data()?)?;
+    if !state.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if &state.seller != seller.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &state.nft_mint != nft_mint.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (escrow_pda, bump_seed) = Pubkey::find_program_address(
+        &[
+            b"escrow",
+            nft_mint.key.as_ref(),
+            seller.key.as_ref(),
+        ],
+        program_id,
+    );
+    if escrow_state.key != &escrow_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Return the NFT from escrow to the seller
+    invoke_signed(
+        &token_instruction::transfer(
+            token_program.key,
+            escrow_token.key,
+            seller_token.key,
+            &escrow_pda,
+            &[],
+            1,
+        )?,
+        &[
+            escrow_token.clone(),
+            seller_token.clone(),
+            escrow_state.clone(),
+            token_program.clone(),
+        ],
+        &[&[
+            b"escrow",
+            nft_mint.key.as_ref(),
+            seller.key.as_ref(),
+            &[bump_seed],
+        ]],
+    )?;
+
+    // Close the escrow state account, returning its rent to the seller
+    let escrow_lamports = escrow_state.lamports();
+    **escrow_state.try_borrow_mut_lamports()? = 0;
+    **seller.try_borrow_mut_lamports()? += escrow_lamports;
+    escrow_state.realloc(0, false)?;
+    escrow_state.assign(&system_program::ID);
+
+    msg!("Listing cancelled, NFT returned to seller");
+    Ok(())
+}
+
+fn process_sweep_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let authority = next_account_info(account_info_iter)?;
+    let treasury = next_account_info(account_info_iter)?;
+    let destination = next_account_info(account_info_iter)?;
+    let program_data = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = next_account_info(account_info_iter)?;
+
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // Restrict sweeping to whoever can currently upgrade this program, rather
+    // than a hard-coded pubkey nobody can rotate without redeploying.
+    let (program_data_pda, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::ID);
+    if program_data.key != &program_data_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let upgrade_authority = match bincode::deserialize(&program_data.try_borrow_data()?) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address,
+            ..
+        }) => upgrade_authority_address,
+        _ => return Err(ProgramError::InvalidAccountData),
+    };
+    if upgrade_authority != Some(*authority.key) {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (treasury_pda, bump_seed) =
+        Pubkey::find_program_address(&[b"treasury", program_id.as_ref()], program_id);
+    if treasury.key != &treasury_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Leave the rent-exempt minimum behind so the treasury PDA never drops
+    // below the runtime's rent-exemption floor, which would make the very
+    // next sale's fee transfer into it fail outright.
+    if rent.key != &rent::ID {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let rent = Rent::from_account_info(rent)?;
+    let amount = treasury
+        .lamports()
+        .saturating_sub(rent.minimum_balance(0));
+    if amount == 0 {
+        msg!("No fees to sweep");
+        return Ok(());
+    }
+
+    invoke_signed(
+        &system_instruction::transfer(treasury.key, destination.key, amount),
+        &[
+            treasury.clone(),
+            destination.clone(),
+            system_program.clone(),
+        ],
+        &[&[b"treasury", program_id.as_ref(), &[bump_seed]]],
+    )?;
+
+    msg!("Swept {} lamports in protocol fees", amount);
+    Ok(())
+}
+
+fn process_place_bid(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let bidder = next_account_info(account_info_iter)?;
+    let bid_payment = next_account_info(account_info_iter)?;
+    let bid_state = next_account_info(account_info_iter)?;
+    let nft_mint = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = next_account_info(account_info_iter)?;
+
+    // Verify the bidder signed the transaction
+    if !bidder.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (bid_payment_pda, _) = Pubkey::find_program_address(
+        &[b"bid", nft_mint.key.as_ref(), bidder.key.as_ref()],
+        program_id,
+    );
+    if bid_payment.key != &bid_payment_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (bid_state_pda, bump_seed) = Pubkey::find_program_address(
+        &[b"bid_state", nft_mint.key.as_ref(), bidder.key.as_ref()],
+        program_id,
+    );
+    if bid_state.key != &bid_state_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // A bid below the rent-exempt minimum for the zero-data escrow-payment PDA
+    // would fail deep inside the transfer below with a confusing runtime error;
+    // reject it up front instead.
+    if rent.key != &rent::ID {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let rent = Rent::from_account_info(rent)?;
+    if amount < rent.minimum_balance(0) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let lamports = rent.minimum_balance(BidState::LEN);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            bidder.key,
+            bid_state.key,
+            lamports,
+            BidState::LEN as u64,
+            program_id,
+        ),
+        &[bidder.clone(), bid_state.clone(), system_program.clone()],
+        &[&[
+            b"bid_state",
+            nft_mint.key.as_ref(),
+            bidder.key.as_ref(),
+            &[bump_seed],
+        ]],
+    )?;
+
+    let state = BidState {
+        is_initialized: true,
+        bidder: *bidder.key,
+        amount,
+    };
+    state.serialize(&mut bid_state.try_borrow_mut_data()?);
+
+    // Escrow the bid amount
+    invoke(
+        &system_instruction::transfer(bidder.key, bid_payment.key, amount),
+        &[bidder.clone(), bid_payment.clone(), system_program.clone()],
+    )?;
+
+    msg!("Bid of {} lamports placed", amount);
+    Ok(())
+}
+
+fn process_accept_bid(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let seller = next_account_info(account_info_iter)?;
+    let escrow_token = next_account_info(account_info_iter)?;
+    let bidder_token = next_account_info(account_info_iter)?;
+    let escrow_state = next_account_info(account_info_iter)?;
+    let nft_mint = next_account_info(account_info_iter)?;
+    let metadata_info = next_account_info(account_info_iter)?;
+    let treasury = next_account_info(account_info_iter)?;
+    let bid_payment = next_account_info(account_info_iter)?;
+    let bid_state = next_account_info(account_info_iter)?;
+    let bidder = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let creator_accounts: Vec<&AccountInfo> = account_info_iter.collect();
+
+    // Verify the seller signed the transaction
+    if !seller.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let listing = EscrowState::deserialize(&escrow_state.try_borrow_data()?)?;
+    if !listing.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if &listing.seller != seller.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &listing.nft_mint != nft_mint.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let bid = BidState::deserialize(&bid_state.try_borrow_data()?)?;
+    if !bid.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if &bid.bidder != bidder.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let price = bid.amount;
+
+    let (bid_payment_pda, payment_bump) = Pubkey::find_program_address(
+        &[b"bid", nft_mint.key.as_ref(), bid.bidder.as_ref()],
+        program_id,
+    );
+    if bid_payment.key != &bid_payment_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (bid_state_pda, _) = Pubkey::find_program_address(
+        &[b"bid_state", nft_mint.key.as_ref(), bid.bidder.as_ref()],
+        program_id,
+    );
+    if bid_state.key != &bid_state_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (metadata_pda, _) = Pubkey::find_program_address(
+        &[
+            b"metadata",
+            mpl_token_metadata::ID.as_ref(),
+            nft_mint.key.as_ref(),
+        ],
+        &mpl_token_metadata::ID,
+    );
+    if metadata_info.key != &metadata_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let metadata = Metadata::from_account_info(metadata_info)?;
+
+    let (treasury_pda, _) = Pubkey::find_program_address(&[b"treasury", program_id.as_ref()], program_id);
+    if treasury.key != &treasury_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let payment_signer_seeds: &[&[u8]] = &[
+        b"bid",
+        nft_mint.key.as_ref(),
+        bid.bidder.as_ref(),
+        &[payment_bump],
+    ];
+
+    // The escrowed bid, not the seller, funds the royalty and fee payments
+    let (royalty_paid, fee) = pay_royalties_and_fee(
+        price,
+        &metadata,
+        &creator_accounts,
+        treasury,
+        bid_payment,
+        &[payment_signer_seeds],
+        system_program,
+    )?;
+
+    let seller_proceeds = price
+        .checked_sub(royalty_paid)
+        .and_then(|remainder| remainder.checked_sub(fee))
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    invoke_signed(
+        &system_instruction::transfer(bid_payment.key, seller.key, seller_proceeds),
+        &[bid_payment.clone(), seller.clone(), system_program.clone()],
+        &[payment_signer_seeds],
+    )?;
+
+    // Transfer the NFT from escrow to the bidder
+    let (escrow_pda, bump_seed) = Pubkey::find_program_address(
+        &[b"escrow", nft_mint.key.as_ref(), seller.key.as_ref()],
+        program_id,
+    );
+    if escrow_state.key != &escrow_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
 
     invoke_signed(
         &token_instruction::transfer(
             token_program.key,
             escrow_token.key,
-            buyer_token.key,
+            bidder_token.key,
             &escrow_pda,
             &[],
             1,
         )?,
         &[
             escrow_token.clone(),
-            buyer_token.clone(),
+            bidder_token.clone(),
             escrow_state.clone(),
             token_program.clone(),
         ],
@@ -175,23 +1086,185 @@ fn process_purchase(
         ]],
     )?;
 
-    msg!("NFT purchased for {} lamports", price);
+    // Close the escrow state account, returning its rent to the seller
+    let escrow_lamports = escrow_state.lamports();
+    **escrow_state.try_borrow_mut_lamports()? = 0;
+    **seller.try_borrow_mut_lamports()? += escrow_lamports;
+    escrow_state.realloc(0, false)?;
+    escrow_state.assign(&system_program::ID);
+
+    // Close the bid state account, returning its rent to the bidder
+    let bid_state_lamports = bid_state.lamports();
+    **bid_state.try_borrow_mut_lamports()? = 0;
+    **bidder.try_borrow_mut_lamports()? += bid_state_lamports;
+    bid_state.realloc(0, false)?;
+    bid_state.assign(&system_program::ID);
+
+    msg!(
+        "Bid of {} lamports accepted ({} in royalties, {} in protocol fees)",
+        price,
+        royalty_paid,
+        fee
+    );
+    Ok(())
+}
+
+fn process_cancel_bid(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let bidder = next_account_info(account_info_iter)?;
+    let bid_payment = next_account_info(account_info_iter)?;
+    let bid_state = next_account_info(account_info_iter)?;
+    let nft_mint = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    // Verify the bidder signed the transaction
+    if !bidder.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let state = BidState::deserialize(&bid_state.try_borrow_data()?)?;
+    if !state.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    if &state.bidder != bidder.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (bid_payment_pda, payment_bump) = Pubkey::find_program_address(
+        &[b"bid", nft_mint.key.as_ref(), bidder.key.as_ref()],
+        program_id,
+    );
+    if bid_payment.key != &bid_payment_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (bid_state_pda, _) = Pubkey::find_program_address(
+        &[b"bid_state", nft_mint.key.as_ref(), bidder.key.as_ref()],
+        program_id,
+    );
+    if bid_state.key != &bid_state_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Refund the escrowed bid
+    invoke_signed(
+        &system_instruction::transfer(bid_payment.key, bidder.key, state.amount),
+        &[
+            bid_payment.clone(),
+            bidder.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[
+            b"bid",
+            nft_mint.key.as_ref(),
+            bidder.key.as_ref(),
+            &[payment_bump],
+        ]],
+    )?;
+
+    // Close the bid state account, returning its rent to the bidder
+    let bid_state_lamports = bid_state.lamports();
+    **bid_state.try_borrow_mut_lamports()? = 0;
+    **bidder.try_borrow_mut_lamports()? += bid_state_lamports;
+    bid_state.realloc(0, false)?;
+    bid_state.assign(&system_program::ID);
+
+    msg!("Bid cancelled, {} lamports refunded", state.amount);
     Ok(())
 }
 
 impl MarketplaceInstruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
         let (&tag, rest) = input.split_first().ok_or(ProgramError::InvalidInstructionData)?;
-        let price = rest
-            .get(..8)
-            .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
-            .ok_or(ProgramError::InvalidInstructionData)?;
 
         Ok(match tag {
-            0 => Self::ListNFT { price },
-            1 => Self::PurchaseNFT { price },
+            0 => Self::ListNFT {
+                price: Self::unpack_price(rest)?,
+                is_pnft: Self::unpack_is_pnft(rest),
+            },
+            1 => Self::PurchaseNFT {
+                price: Self::unpack_price(rest)?,
+                is_pnft: Self::unpack_is_pnft(rest),
+            },
+            2 => Self::CancelListing,
+            3 => Self::SweepFees,
+            4 => Self::PlaceBid {
+                amount: Self::unpack_price(rest)?,
+            },
+            5 => Self::AcceptBid,
+            6 => Self::CancelBid,
             _ => return Err(ProgramError::InvalidInstructionData),
         })
     }
+
+    fn unpack_price(rest: &[u8]) -> Result<u64, ProgramError> {
+        rest.get(..8)
+            .and_then(|slice| slice.try_into().ok())
+            .map(u64::from_le_bytes)
+            .ok_or(ProgramError::InvalidInstructionData)
+    }
+
+    /// The `is_pnft` flag is an optional trailing byte after the price, for
+    /// backwards compatibility with callers that only send the first 8 bytes.
+    fn unpack_is_pnft(rest: &[u8]) -> bool {
+        rest.get(8).map(|&flag| flag != 0).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escrow_state_round_trip() {
+        let state = EscrowState {
+            is_initialized: true,
+            seller: Pubkey::new_unique(),
+            nft_mint: Pubkey::new_unique(),
+            price: 123_456_789,
+        };
+        let mut buf = [0u8; EscrowState::LEN];
+        state.serialize(&mut buf);
+        assert_eq!(EscrowState::deserialize(&buf).unwrap(), state);
+    }
+
+    #[test]
+    fn bid_state_round_trip() {
+        let state = BidState {
+            is_initialized: true,
+            bidder: Pubkey::new_unique(),
+            amount: 42,
+        };
+        let mut buf = [0u8; BidState::LEN];
+        state.serialize(&mut buf);
+        assert_eq!(BidState::deserialize(&buf).unwrap(), state);
+    }
+
+    #[test]
+    fn split_sale_proceeds_basic() {
+        // 5% royalty split 60/40 between two creators, 2.5% protocol fee.
+        let (creator_shares, royalty_paid, fee) =
+            split_sale_proceeds(1_000_000, 500, &[60, 40]).unwrap();
+        assert_eq!(creator_shares, vec![30_000, 20_000]);
+        assert_eq!(royalty_paid, 50_000);
+        assert_eq!(fee, 25_000);
+    }
+
+    #[test]
+    fn split_sale_proceeds_rounds_down_and_drops_dust() {
+        // A price and bps combination that doesn't divide evenly: every
+        // amount rounds down, so no lamports are invented, and the royalty
+        // dust left over (49 computed, 48 paid) goes to no creator.
+        let (creator_shares, royalty_paid, fee) = split_sale_proceeds(999, 500, &[50, 50]).unwrap();
+        assert_eq!(creator_shares, vec![24, 24]);
+        assert_eq!(royalty_paid, 48);
+        assert_eq!(fee, 24);
+    }
+
+    #[test]
+    fn split_sale_proceeds_zero_creators() {
+        let (creator_shares, royalty_paid, fee) = split_sale_proceeds(1_000_000, 500, &[]).unwrap();
+        assert!(creator_shares.is_empty());
+        assert_eq!(royalty_paid, 0);
+        assert_eq!(fee, 25_000);
+    }
 } 
\ No newline at end of file